@@ -10,11 +10,15 @@
 //! Core Foundation byte buffers.
 
 pub use core_foundation_sys::data::*;
-use core_foundation_sys::base::{CFIndex, CFRange};
+use core_foundation_sys::base::{CFIndex, CFRange, CFRelease, CFTypeRef};
 use core_foundation_sys::base::{kCFAllocatorDefault};
+use core_foundation_sys::base::{CFAllocatorContext, CFAllocatorCreate};
+use std::io;
 use std::mem;
-use std::ops::{Deref, DerefMut};
+use std::ops::{Deref, DerefMut, Range};
+use std::os::raw::c_void;
 use std::slice;
+use std::sync::Arc;
 
 use base::{CFIndexConvertible, TCFType};
 
@@ -36,6 +40,60 @@ impl CFData {
         }
     }
 
+    /// Creates a `CFData` by taking ownership of `buffer`'s storage, without copying it.
+    ///
+    /// The buffer is only freed once the last reference to the returned `CFData` is
+    /// dropped, so this is an O(1) way to hand a large, Rust-allocated buffer to a CF API.
+    pub fn from_vec(buffer: Vec<u8>) -> CFData {
+        if buffer.is_empty() {
+            // An empty `Vec`'s pointer isn't guaranteed valid to hand to CF, and there's
+            // nothing to save by avoiding a copy of zero bytes anyway.
+            return CFData::from_buffer(&buffer);
+        }
+        unsafe { CFData::from_boxed_owner(Box::new(buffer)) }
+    }
+
+    /// Creates a `CFData` sharing ownership of `buffer`'s storage with the rest of the
+    /// program, without copying it. Other clones of `buffer` remain valid for as long as
+    /// the returned `CFData` is kept alive.
+    pub fn from_arc(buffer: Arc<Vec<u8>>) -> CFData {
+        if buffer.is_empty() {
+            return CFData::from_buffer(&buffer);
+        }
+        unsafe { CFData::from_boxed_owner(Box::new(buffer)) }
+    }
+
+    /// Wraps the bytes behind `owner` in a `CFData` without copying them, using a custom
+    /// `CFAllocator` as the data's `bytesDeallocator` so that `owner` is dropped exactly
+    /// once, when CF is done with the buffer.
+    ///
+    /// `T` is bounded via `Deref` rather than `AsRef<[u8]>` directly so that both
+    /// `Vec<u8>` (whose target is already `[u8]`) and `Arc<Vec<u8>>` (whose target is
+    /// `Vec<u8>`, itself `AsRef<[u8]>`) can be used as the owner.
+    unsafe fn from_boxed_owner<T>(owner: Box<T>) -> CFData
+        where T: Deref, T::Target: AsRef<[u8]>
+    {
+        let bytes = (**owner).as_ref();
+        let ptr = bytes.as_ptr();
+        let len = bytes.len();
+
+        let mut context: CFAllocatorContext = mem::zeroed();
+        context.info = Box::into_raw(owner) as *mut c_void;
+        context.deallocate = Some(release_boxed_owner::<T>);
+
+        // This allocator's only job is to run `release_boxed_owner` once; it is never used
+        // to allocate anything.
+        let bytes_deallocator = CFAllocatorCreate(kCFAllocatorDefault, &mut context);
+        let data_ref = CFDataCreateWithBytesNoCopy(kCFAllocatorDefault,
+                                                    ptr,
+                                                    len.to_CFIndex(),
+                                                    bytes_deallocator);
+        // `CFDataCreateWithBytesNoCopy` retains `bytes_deallocator` for as long as it needs
+        // it; release our own reference to it now.
+        CFRelease(bytes_deallocator as CFTypeRef);
+        TCFType::wrap_under_create_rule(data_ref)
+    }
+
     /// Returns a pointer to the underlying bytes in this data. Note that this byte buffer is
     /// read-only.
     #[inline]
@@ -52,6 +110,41 @@ impl CFData {
             CFDataGetLength(self.0)
         }
     }
+
+    /// Returns a cursor over this buffer's bytes that implements `std::io::Read`, so a
+    /// `CFData` received from a CF API can be handed straight to a parser expecting `Read`.
+    #[inline]
+    pub fn reader(&self) -> CFDataReader {
+        CFDataReader { data: self, pos: 0 }
+    }
+
+    /// Copies the bytes in `range` into `buf`, without copying or exposing the rest of this
+    /// buffer. Panics if `range` is out of bounds or doesn't have the same length as `buf`.
+    pub fn get_bytes(&self, range: Range<usize>, buf: &mut [u8]) {
+        assert!(range.start <= range.end);
+        assert!(range.end <= self.len() as usize);
+        assert_eq!(range.end - range.start, buf.len());
+        unsafe {
+            CFDataGetBytes(self.0,
+                            CFRange {
+                                location: range.start.to_CFIndex(),
+                                length: (range.end - range.start).to_CFIndex(),
+                            },
+                            buf.as_mut_ptr());
+        }
+    }
+
+    /// Returns a new `CFData` holding a copy of just the bytes in `range`, without copying
+    /// (or retaining a reference to) the rest of this buffer.
+    ///
+    /// For a *borrowed* window into this buffer's bytes that doesn't copy anything, slice
+    /// `&self[range]` directly through the `Deref<Target = [u8]>` impl instead.
+    pub fn subrange_copy(&self, range: Range<usize>) -> CFData {
+        assert!(range.start <= range.end);
+        let mut buf = vec![0; range.end - range.start];
+        self.get_bytes(range, &mut buf);
+        CFData::from_buffer(&buf)
+    }
 }
 
 impl Deref for CFData {
@@ -63,6 +156,24 @@ impl Deref for CFData {
     }
 }
 
+/// A cursor over the bytes of a borrowed `CFData`, implementing `std::io::Read`.
+///
+/// Obtained through `CFData::reader`.
+pub struct CFDataReader<'a> {
+    data: &'a CFData,
+    pos: usize,
+}
+
+impl<'a> io::Read for CFDataReader<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let remaining = &self.data.bytes()[self.pos..];
+        let len = usize::min(buf.len(), remaining.len());
+        buf[..len].copy_from_slice(&remaining[..len]);
+        self.pos += len;
+        Ok(len)
+    }
+}
+
 declare_TCFType!{
     /// A mutable byte buffer.
     ///
@@ -134,6 +245,46 @@ impl CFMutableData {
         }
     }
 
+    /// Returns `len()`.
+    ///
+    /// Unlike `Vec::capacity`, **this is not a usable headroom figure**: Core Foundation
+    /// doesn't expose a mutable data's true allocated capacity, only its current length, so
+    /// `len()` is the only number this method has to return. In particular,
+    /// `capacity() - len()` is always `0`, even immediately after a `reserve` call that grew
+    /// the real, unobservable allocation — so the common `Vec`/arrow idiom of checking
+    /// `capacity() - len() < needed` before reserving does not work here and will reserve on
+    /// every call. This method exists only to round out the `reserve`/`push`/`capacity`
+    /// trio; prefer just calling `reserve` unconditionally with your expected growth.
+    #[inline]
+    pub fn capacity(&self) -> CFIndex {
+        self.len()
+    }
+
+    /// Pre-extends this buffer's underlying allocation by `additional` bytes, so that a tight
+    /// loop of small appends (e.g. `push`) doesn't reallocate on every call.
+    ///
+    /// This works by growing this buffer's length by `additional` and immediately shrinking
+    /// it back down, which is enough to make Core Foundation grow its backing allocation
+    /// without changing `len()`. If this instance has a maximum capacity set through
+    /// `with_maximum_capacity`, growth beyond that cap is clamped by Core Foundation itself.
+    pub fn reserve(&mut self, additional: usize) {
+        let len = self.len() as usize;
+        self.set_len(len + additional);
+        self.set_len(len);
+    }
+
+    /// Appends a single byte to this buffer.
+    ///
+    /// This is a thin wrapper around `extend_from_slice` for the single-byte case; it still
+    /// makes one `CFDataReplaceBytes` call per byte, so it does not by itself amortize the
+    /// FFI overhead of pushing a byte at a time. Calling `reserve` beforehand only avoids
+    /// reallocating the underlying buffer's backing storage as it grows, not that per-call
+    /// cost.
+    #[inline]
+    pub fn push(&mut self, byte: u8) {
+        self.extend_from_slice(&[byte]);
+    }
+
     /// Converts this `CFMutableData` into its immutable counterpart.
     ///
     /// *Note:* This method consumes self, because having a `CFData` and a `CFMutableData`
@@ -146,6 +297,29 @@ impl CFMutableData {
     }
 }
 
+impl io::Write for CFMutableData {
+    /// Appends `buf` to this buffer and returns how many bytes were actually appended.
+    ///
+    /// This never fails, but it can be a short write: if this buffer has a maximum capacity
+    /// set via `with_maximum_capacity` and `buf` would grow it past that cap,
+    /// `CFDataReplaceBytes` silently clamps the append, so the returned count — measured
+    /// from the buffer's length before and after, not assumed to be `buf.len()` — may be
+    /// less than `buf.len()`. Callers that need to detect this should compare the returned
+    /// count against `buf.len()`, per `Write`'s usual short-write contract; `write_all` does
+    /// this already and will report a `WriteZero` error.
+    #[inline]
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len_before = self.len() as usize;
+        self.extend_from_slice(buf);
+        Ok(self.len() as usize - len_before)
+    }
+
+    #[inline]
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
 impl Deref for CFMutableData {
     type Target = [u8];
 
@@ -161,3 +335,218 @@ impl DerefMut for CFMutableData {
         self.bytes_mut()
     }
 }
+
+#[cfg(feature = "with-bytes")]
+impl<'a> bytes::Buf for CFDataReader<'a> {
+    #[inline]
+    fn remaining(&self) -> usize {
+        self.data.len() as usize - self.pos
+    }
+
+    #[inline]
+    fn chunk(&self) -> &[u8] {
+        &self.data.bytes()[self.pos..]
+    }
+
+    #[inline]
+    fn advance(&mut self, cnt: usize) {
+        self.pos += cnt;
+    }
+}
+
+/// `CFMutableData`'s `BufMut` impl reserves one byte at a time rather than in bulk chunks.
+///
+/// Core Foundation only exposes a single length counter — there's no separate "reserved
+/// capacity" the way `Vec` has `len` vs. `capacity` — so `chunk_mut` can't hand out
+/// write-ahead space without first actually growing the buffer's externally-visible `len()`
+/// by that same amount. Growing by a whole chunk at once runs into two problems: if the
+/// buffer has a `with_maximum_capacity` cap, `CFDataSetLength` silently clamps instead of
+/// growing the full amount, so a chunk sized to the request rather than to what was actually
+/// achieved would claim more valid memory than exists; and any un-advanced growth (e.g. the
+/// caller errors out between `chunk_mut` and `advance_mut`) permanently leaks zero bytes into
+/// `len()`. Reserving a single byte at a time keeps both failure modes bounded to "at most
+/// one stray zero byte" instead of a whole chunk's worth, and `chunk_mut` always sizes its
+/// `UninitSlice` to the growth `CFDataSetLength` actually achieved rather than assuming it
+/// succeeded in full.
+///
+/// *Caveat:* `remaining_mut` has no way to query a `with_maximum_capacity` buffer's real,
+/// CF-enforced cap (Core Foundation doesn't expose one), so it always reports an effectively
+/// unbounded amount of space remaining — it is only accurate for buffers created with
+/// `CFMutableData::new`. A generic `BufMut` consumer that trusts `remaining_mut` before
+/// calling `put_slice`/`put_*` past a configured maximum capacity would otherwise spin
+/// forever once `chunk_mut` started returning empty slices; instead, `chunk_mut` panics as
+/// soon as it can't reserve at least one more byte, so such a write fails loudly rather than
+/// hanging or silently truncating.
+#[cfg(feature = "with-bytes")]
+unsafe impl bytes::BufMut for CFMutableData {
+    #[inline]
+    fn remaining_mut(&self) -> usize {
+        usize::max_value() - self.len() as usize
+    }
+
+    #[inline]
+    unsafe fn advance_mut(&mut self, cnt: usize) {
+        debug_assert!(cnt <= 1);
+        // The reserved byte, if any, was already committed to `len()` by `chunk_mut`; there
+        // is nothing left to do here.
+    }
+
+    fn chunk_mut(&mut self) -> &mut bytes::buf::UninitSlice {
+        let len = self.len() as usize;
+        self.set_len(len + 1);
+        // `set_len` clamps at a configured maximum capacity instead of growing the full
+        // amount requested, so read back how much was actually reserved rather than
+        // assuming it was 1 byte.
+        let reserved = self.len() as usize - len;
+        assert!(reserved > 0,
+                "CFMutableData::chunk_mut: buffer is already at its configured maximum capacity");
+        unsafe {
+            bytes::buf::UninitSlice::from_raw_parts_mut(self.bytes_mut()[len..].as_mut_ptr(), reserved)
+        }
+    }
+}
+
+/// The `CFAllocatorContext` deallocate callback for the allocator produced by
+/// `CFData::from_boxed_owner`. Ignores the byte pointer CF hands back, since `info` is the
+/// real owner of that memory.
+unsafe extern "C" fn release_boxed_owner<T>(_ptr: *mut c_void, info: *mut c_void) {
+    drop(Box::from_raw(info as *mut T));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn from_vec_exposes_the_same_bytes() {
+        let data = CFData::from_vec(vec![1, 2, 3, 4]);
+        assert_eq!(data.bytes(), &[1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn from_arc_drops_its_owner_exactly_once() {
+        let buffer = Arc::new(vec![1, 2, 3, 4]);
+        let data = CFData::from_arc(buffer.clone());
+        assert_eq!(data.bytes(), &[1, 2, 3, 4][..]);
+        // The custom `CFAllocator`'s `info` holds a second, boxed clone of `buffer`.
+        assert_eq!(Arc::strong_count(&buffer), 2);
+
+        drop(data);
+        // Dropping `data` must have run `release_boxed_owner` exactly once, dropping that
+        // boxed clone and bringing the strong count back down.
+        assert_eq!(Arc::strong_count(&buffer), 1);
+    }
+
+    #[cfg(feature = "with-bytes")]
+    #[test]
+    fn buf_mut_fills_up_to_a_maximum_capacity() {
+        use bytes::BufMut;
+
+        let mut data = CFMutableData::with_maximum_capacity(4);
+        data.put_slice(&[1, 2, 3, 4]);
+        assert_eq!(data.bytes(), &[1, 2, 3, 4][..]);
+    }
+
+    #[cfg(feature = "with-bytes")]
+    #[test]
+    #[should_panic(expected = "maximum capacity")]
+    fn buf_mut_panics_rather_than_hang_past_a_maximum_capacity() {
+        use bytes::BufMut;
+
+        // `remaining_mut` can't see the configured cap and so never reports it as
+        // exhausted; a generic `BufMut` consumer like `put_slice`'s default impl relies on
+        // `chunk_mut` to enforce the cap instead, by panicking once it can't reserve any
+        // more room, rather than returning an empty slice that would make such a consumer
+        // spin forever.
+        let mut data = CFMutableData::with_maximum_capacity(4);
+        data.put_slice(&[1, 2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn write_then_read_back_round_trips() {
+        use std::io::{Read, Write};
+
+        let mut data = CFMutableData::new();
+        let n = data.write(&[1, 2, 3, 4]).unwrap();
+        assert_eq!(n, 4);
+        data.flush().unwrap();
+
+        let immutable = data.into_immutable();
+        let mut out = [0u8; 4];
+        let mut reader = immutable.reader();
+        assert_eq!(reader.read(&mut out).unwrap(), 4);
+        assert_eq!(out, [1, 2, 3, 4]);
+        // The cursor is exhausted now.
+        assert_eq!(reader.read(&mut out).unwrap(), 0);
+    }
+
+    #[test]
+    fn write_reports_a_short_write_at_the_maximum_capacity() {
+        use std::io::Write;
+
+        let mut data = CFMutableData::with_maximum_capacity(2);
+        let n = data.write(&[1, 2, 3, 4]).unwrap();
+        assert_eq!(n, 2);
+        assert_eq!(data.bytes(), &[1, 2][..]);
+    }
+
+    #[test]
+    fn get_bytes_copies_the_requested_window() {
+        let data = CFData::from_buffer(&[10, 20, 30, 40, 50]);
+        let mut buf = [0u8; 3];
+        data.get_bytes(1..4, &mut buf);
+        assert_eq!(buf, [20, 30, 40]);
+    }
+
+    #[test]
+    #[should_panic]
+    fn get_bytes_panics_on_an_inverted_range() {
+        let data = CFData::from_buffer(&[1, 2, 3]);
+        let mut buf = [0u8; 0];
+        data.get_bytes(2..1, &mut buf);
+    }
+
+    #[test]
+    #[should_panic]
+    fn get_bytes_panics_out_of_bounds() {
+        let data = CFData::from_buffer(&[1, 2, 3]);
+        let mut buf = [0u8; 1];
+        data.get_bytes(3..4, &mut buf);
+    }
+
+    #[test]
+    fn subrange_copy_returns_just_the_requested_window() {
+        let data = CFData::from_buffer(&[10, 20, 30, 40, 50]);
+        let copy = data.subrange_copy(1..4);
+        assert_eq!(copy.bytes(), &[20, 30, 40]);
+    }
+
+    #[test]
+    #[should_panic]
+    fn subrange_copy_panics_on_an_inverted_range() {
+        let data = CFData::from_buffer(&[1, 2, 3]);
+        data.subrange_copy(2..1);
+    }
+
+    #[test]
+    fn push_appends_one_byte_at_a_time() {
+        let mut data = CFMutableData::new();
+        data.push(1);
+        data.push(2);
+        data.push(3);
+        assert_eq!(data.bytes(), &[1, 2, 3][..]);
+    }
+
+    #[test]
+    fn reserve_does_not_change_len_or_existing_bytes() {
+        let mut data = CFMutableData::new();
+        data.extend_from_slice(&[1, 2, 3]);
+        data.reserve(64);
+        assert_eq!(data.len(), 3);
+        assert_eq!(data.bytes(), &[1, 2, 3][..]);
+        assert_eq!(data.capacity(), data.len());
+
+        data.push(4);
+        assert_eq!(data.bytes(), &[1, 2, 3, 4][..]);
+    }
+}